@@ -1,13 +1,14 @@
 use anyhow::Error;
 use bytes::Bytes;
-use futures::executor::block_on;
+use futures::StreamExt;
 use http::{HeaderMap, HeaderValue};
 use reqwest::{Client, Method};
-use std::cell::RefCell;
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::pin::Pin;
 use std::str::FromStr;
-use tokio::runtime::Handle;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::Instrument;
 use url::Url;
 use wasmtime::*;
 
@@ -15,9 +16,42 @@ const MEMORY: &str = "memory";
 
 pub type WasiHandle = u32;
 
+type ByteStream = Pin<Box<dyn futures::Stream<Item = reqwest::Result<Bytes>> + Send>>;
+
+/// A response body read incrementally by the guest.
+///
+/// `residual` holds the bytes of the current chunk not yet handed out; once it
+/// is drained, the next chunk is pulled from `stream` on demand. A fully
+/// buffered body (e.g. one that had to be decompressed up front) simply has no
+/// `stream` and keeps all of its bytes in `residual`.
 struct Body {
-    bytes: Vec<u8>,
+    stream: Option<ByteStream>,
+    residual: Bytes,
     pos: usize,
+    /// Maximum time to wait for the next chunk from the server, if bounded.
+    read_timeout: Option<Duration>,
+}
+
+impl Body {
+    /// A body whose bytes are already fully in memory.
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        Body {
+            stream: None,
+            residual: Bytes::from(bytes),
+            pos: 0,
+            read_timeout: None,
+        }
+    }
+
+    /// A body streamed chunk-by-chunk from the upstream response.
+    fn from_response(res: reqwest::Response, read_timeout: Option<Duration>) -> Self {
+        Body {
+            stream: Some(Box::pin(res.bytes_stream())),
+            residual: Bytes::new(),
+            pos: 0,
+            read_timeout,
+        }
+    }
 }
 
 struct Response {
@@ -59,6 +93,60 @@ enum HttpError {
     RuntimeError,
     #[error("Too many sessions")]
     TooManySessions,
+    #[error("Content decoding error")]
+    DecodingError,
+    #[error("Request timed out")]
+    Timeout,
+}
+
+/// Client-side policy applied to every outbound request.
+///
+/// Mirrors the knobs a server exposes for slow-client handling, but on the
+/// requesting side: how long to wait for a connection, for the response head
+/// and for each body chunk, how many redirects to follow, and how many times to retry an
+/// idempotent request before giving up.
+#[derive(Clone, Copy, Debug)]
+pub struct HttpConfig {
+    /// Maximum time to wait while establishing a connection.
+    pub connect_timeout: Option<Duration>,
+    /// Maximum time to wait to connect and receive the response head.
+    ///
+    /// This bounds the connect + request-send + response-header phase only. It
+    /// deliberately does **not** cover reading the response body, which is
+    /// streamed lazily across guest-paced `body_read` calls; otherwise a guest
+    /// that pauses between reads would trip the timeout mid-stream. Use
+    /// [`read_timeout`](Self::read_timeout) to bound the per-chunk wait instead.
+    /// The timeout applies to each attempt separately: with
+    /// [`retries`](Self::retries), the clock restarts on every retry.
+    pub timeout: Option<Duration>,
+    /// Maximum time to wait for the next body chunk to arrive from the server.
+    ///
+    /// This bounds the *server's* latency between chunks, not the guest's pace:
+    /// the clock only runs while a `body_read` is blocked waiting on the
+    /// upstream (or, for a body buffered up front for decompression, while the
+    /// host reads it). It closes the "headers then hang mid-body" failure mode
+    /// that [`timeout`](Self::timeout), which stops at the response head, does
+    /// not cover.
+    pub read_timeout: Option<Duration>,
+    /// Maximum number of redirects to follow.
+    pub max_redirects: usize,
+    /// Number of extra attempts for idempotent methods on failure.
+    pub retries: u32,
+    /// Base delay between retries; grows linearly with the attempt count.
+    pub backoff: Duration,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            connect_timeout: None,
+            timeout: None,
+            read_timeout: None,
+            max_redirects: 10,
+            retries: 0,
+            backoff: Duration::from_millis(100),
+        }
+    }
 }
 
 impl From<HttpError> for u32 {
@@ -77,6 +165,8 @@ impl From<HttpError> for u32 {
             HttpError::RequestError(_) => 11,
             HttpError::RuntimeError => 12,
             HttpError::TooManySessions => 13,
+            HttpError::DecodingError => 14,
+            HttpError::Timeout => 15,
         }
     }
 }
@@ -108,38 +198,103 @@ struct HostCalls;
 
 impl HostCalls {
     fn close(
-        st: Rc<RefCell<State>>,
+        st: Arc<Mutex<State>>,
         _caller: Caller<'_>,
         handle: WasiHandle,
     ) -> Result<(), HttpError> {
-        st.borrow_mut().responses.remove(&handle);
+        st.lock().unwrap().responses.remove(&handle);
         Ok(())
     }
 
-    fn body_read(
-        st: Rc<RefCell<State>>,
+    /// Copy the next available bytes of the response body into guest memory and
+    /// write the number of bytes copied to `buf_read_ptr`.
+    ///
+    /// The `buf_read` protocol is unchanged from the fully-buffered version:
+    /// the guest reads in a loop until `buf_read` is written as `0`, which
+    /// signals end-of-body. What *did* change with streaming is that a single
+    /// call now returns at most one upstream chunk, so a short read
+    /// (`buf_read < buf_len`) is normal and does **not** imply EOF — only a
+    /// `0`-byte read does. Guests that assumed the old path always filled the
+    /// buffer until the body was exhausted must loop on `buf_read != 0`.
+    async fn body_read(
+        st: Arc<Mutex<State>>,
         caller: Caller<'_>,
         handle: WasiHandle,
         buf_ptr: u32,
         buf_len: u32,
         buf_read_ptr: u32,
     ) -> Result<(), HttpError> {
-        let mut st = st.borrow_mut();
-        let mut body = &mut st
-            .responses
-            .get_mut(&handle)
-            .ok_or(HttpError::InvalidHandle(handle))?
-            .body;
         let memory = memory_get(caller)?;
-        let available = std::cmp::min(buf_len as _, body.bytes.len() - body.pos);
-        memory.write(buf_ptr as _, &body.bytes[body.pos..body.pos + available])?;
-        body.pos += available;
-        memory.write(buf_read_ptr as _, &(available as u32).to_le_bytes())?;
-        Ok(())
+        loop {
+            // Serve whatever is left in the current chunk first.
+            {
+                let mut st = st.lock().unwrap();
+                let body = &mut st
+                    .responses
+                    .get_mut(&handle)
+                    .ok_or(HttpError::InvalidHandle(handle))?
+                    .body;
+                if body.pos < body.residual.len() {
+                    let available = std::cmp::min(buf_len as _, body.residual.len() - body.pos);
+                    memory.write(buf_ptr as _, &body.residual[body.pos..body.pos + available])?;
+                    body.pos += available;
+                    memory.write(buf_read_ptr as _, &(available as u32).to_le_bytes())?;
+                    return Ok(());
+                }
+            }
+
+            // Current chunk drained; pull the next one from the stream, if any.
+            let (stream, read_timeout) = {
+                let mut st = st.lock().unwrap();
+                let body = &mut st
+                    .responses
+                    .get_mut(&handle)
+                    .ok_or(HttpError::InvalidHandle(handle))?
+                    .body;
+                (body.stream.take(), body.read_timeout)
+            };
+            let mut stream = match stream {
+                Some(s) => s,
+                None => {
+                    // Fully consumed: signal EOF.
+                    memory.write(buf_read_ptr as _, &0u32.to_le_bytes())?;
+                    return Ok(());
+                }
+            };
+            // Bound the wait on the server, not the guest: the timeout only
+            // covers this in-flight `next()`, which is pending precisely while
+            // the server owes us bytes.
+            let next = match read_timeout {
+                Some(t) => match tokio::time::timeout(t, stream.next()).await {
+                    Ok(next) => next,
+                    Err(_elapsed) => return Err(HttpError::Timeout),
+                },
+                None => stream.next().await,
+            };
+            match next {
+                Some(Ok(chunk)) => {
+                    let mut st = st.lock().unwrap();
+                    let body = &mut st
+                        .responses
+                        .get_mut(&handle)
+                        .ok_or(HttpError::InvalidHandle(handle))?
+                        .body;
+                    body.residual = chunk;
+                    body.pos = 0;
+                    body.stream = Some(stream);
+                    // Loop back to serve the freshly fetched chunk.
+                }
+                Some(Err(e)) => return Err(map_reqwest_error(e)),
+                None => {
+                    memory.write(buf_read_ptr as _, &0u32.to_le_bytes())?;
+                    return Ok(());
+                }
+            }
+        }
     }
 
     fn header_get(
-        st: Rc<RefCell<State>>,
+        st: Arc<Mutex<State>>,
         caller: Caller<'_>,
         handle: WasiHandle,
         name_ptr: u32,
@@ -148,7 +303,7 @@ impl HostCalls {
         value_len: u32,
         value_written_ptr: u32,
     ) -> Result<(), HttpError> {
-        let st = st.borrow();
+        let st = st.lock().unwrap();
         let headers = &st
             .responses
             .get(&handle)
@@ -165,9 +320,54 @@ impl HostCalls {
         Ok(())
     }
 
-    fn req(
-        st: Rc<RefCell<State>>,
-        allowed_hosts: Option<&[String]>,
+    fn header_names(
+        st: Arc<Mutex<State>>,
+        caller: Caller<'_>,
+        handle: WasiHandle,
+        buf_ptr: u32,
+        buf_len: u32,
+        buf_written_ptr: u32,
+    ) -> Result<(), HttpError> {
+        let st = st.lock().unwrap();
+        let headers = &st
+            .responses
+            .get(&handle)
+            .ok_or(HttpError::InvalidHandle(handle))?
+            .headers;
+        let memory = memory_get(caller)?;
+        let names: Vec<&str> = headers.keys().map(|k| k.as_str()).collect();
+        let serialized = names.join("\n");
+        if serialized.len() > buf_len as _ {
+            return Err(HttpError::BufferTooSmall);
+        }
+        memory.write(buf_ptr as _, serialized.as_bytes())?;
+        memory.write(buf_written_ptr as _, &(serialized.len() as u32).to_le_bytes())?;
+        Ok(())
+    }
+
+    fn header_count(
+        st: Arc<Mutex<State>>,
+        caller: Caller<'_>,
+        handle: WasiHandle,
+        count_ptr: u32,
+    ) -> Result<(), HttpError> {
+        let st = st.lock().unwrap();
+        let headers = &st
+            .responses
+            .get(&handle)
+            .ok_or(HttpError::InvalidHandle(handle))?
+            .headers;
+        let memory = memory_get(caller)?;
+        let count = headers.keys().count() as u32;
+        memory.write(count_ptr as _, &count.to_le_bytes())?;
+        Ok(())
+    }
+
+    async fn req(
+        st: Arc<Mutex<State>>,
+        allowed_hosts: Arc<Option<Vec<String>>>,
+        decompress_responses: bool,
+        config: HttpConfig,
         caller: Caller<'_>,
         url_ptr: u32,
         url_len: u32,
@@ -180,13 +380,13 @@ impl HostCalls {
         status_code_ptr: u32,
         res_handle_ptr: u32,
     ) -> Result<(), HttpError> {
-        let span = tracing::trace_span!("req");
-        let _enter = span.enter();
         let memory = memory_get(caller)?;
-        let url = string_from_memory(&memory, url_ptr, url_len)?;
+        // Copy everything out of guest memory up front so nothing borrows the
+        // caller across the `.await` below.
+        let url = string_from_memory(&memory, url_ptr, url_len)?.to_owned();
         let method = Method::from_str(string_from_memory(&memory, method_ptr, method_len)?)
             .map_err(|_| HttpError::InvalidMethod)?;
-        let req_body = slice_from_memory(&memory, req_body_ptr, req_body_len)?;
+        let req_body = slice_from_memory(&memory, req_body_ptr, req_body_len)?.to_vec();
         let headers = wasi_experimental_http::string_to_header_map(string_from_memory(
             &memory,
             req_headers_ptr,
@@ -194,28 +394,51 @@ impl HostCalls {
         )?)
         .map_err(|_| HttpError::InvalidEncoding)?;
 
-        if !is_allowed(url, allowed_hosts)? {
-            return Err(HttpError::DestinationNotAllowed(url.to_string()));
+        if !is_allowed(&url, allowed_hosts.as_deref())? {
+            return Err(HttpError::DestinationNotAllowed(url));
         }
 
-        let (status, resp_headers, resp_body) = request(url, headers, method, req_body)?;
-        tracing::debug!(
-            status,
-            ?resp_headers,
-            body_len = resp_body.as_ref().len(),
-            "got HTTP response, writing back to memory"
-        );
+        // Instrument the request future rather than holding a span guard across
+        // the `.await`, which would leak the span onto other tasks polled on
+        // this thread while the request is suspended.
+        let span = tracing::trace_span!("req");
+        let (status, mut resp_headers, res) = request(&url, headers, method, req_body, &config)
+            .instrument(span)
+            .await?;
+        tracing::debug!(status, ?resp_headers, "got HTTP response, writing back to memory");
+
+        // When the response advertises a supported `Content-Encoding` and the
+        // host is configured to decode it, the whole body has to be buffered so
+        // it can be inflated. Otherwise the body is streamed chunk-by-chunk so
+        // host memory stays bounded regardless of response size.
+        let body = if decompress_responses && supported_encoding(&resp_headers) {
+            // Apply the same per-chunk bound as a streamed body would.
+            let mut stream = Box::pin(res.bytes_stream());
+            let mut raw = Vec::new();
+            loop {
+                let next = match config.read_timeout {
+                    Some(t) => tokio::time::timeout(t, stream.next())
+                        .await
+                        .map_err(|_| HttpError::Timeout)?,
+                    None => stream.next().await,
+                };
+                match next {
+                    Some(chunk) => raw.extend_from_slice(&chunk.map_err(map_reqwest_error)?),
+                    None => break,
+                }
+            }
+            Body::from_bytes(decode_response_body(&mut resp_headers, Bytes::from(raw))?)
+        } else {
+            Body::from_response(res, config.read_timeout)
+        };
 
         memory.write(status_code_ptr as _, &status.to_le_bytes())?;
 
         let response = Response {
             headers: resp_headers,
-            body: Body {
-                bytes: resp_body.to_vec(),
-                pos: 0,
-            },
+            body,
         };
-        let mut st = st.borrow_mut();
+        let mut st = st.lock().unwrap();
         let initial_handle = st.current_handle;
         while st.responses.get(&st.current_handle).is_some() {
             st.current_handle += 1;
@@ -231,23 +454,53 @@ impl HostCalls {
     }
 }
 
+/// Host implementation of the `wasi_experimental_http` module.
+///
+/// The `req` and `body_read` host calls are registered as **async** host
+/// functions (`Linker::func_async`), so the embedder must build its
+/// [`wasmtime::Store`] from a [`wasmtime::Config`] with
+/// [`async_support(true)`](wasmtime::Config::async_support) and invoke the
+/// guest with the `call_async` family. Calling such a guest from a synchronous
+/// `Store` will fail at instantiation/call time.
 pub struct Http {
-    state: Rc<RefCell<State>>,
-    allowed_hosts: Rc<Option<Vec<String>>>,
+    state: Arc<Mutex<State>>,
+    allowed_hosts: Arc<Option<Vec<String>>>,
+    decompress_responses: bool,
+    config: HttpConfig,
 }
 
 impl Http {
     pub const MODULE: &'static str = "wasi_experimental_http";
 
+    /// Create a host with the default policy: automatic response decompression
+    /// enabled and a default [`HttpConfig`]. Use [`Http::new_with_config`] to
+    /// opt out of decompression or customize timeouts, redirects, and retries.
     pub fn new(allowed_hosts: Option<Vec<String>>) -> Result<Self, Error> {
-        let state = Rc::new(RefCell::new(State::default()));
-        let allowed_hosts = Rc::new(allowed_hosts);
+        Self::new_with_config(allowed_hosts, true, HttpConfig::default())
+    }
+
+    /// Create a host with explicit control over response decompression and the
+    /// request policy.
+    pub fn new_with_config(
+        allowed_hosts: Option<Vec<String>>,
+        decompress_responses: bool,
+        config: HttpConfig,
+    ) -> Result<Self, Error> {
+        let state = Arc::new(Mutex::new(State::default()));
+        let allowed_hosts = Arc::new(allowed_hosts);
         Ok(Http {
             state,
             allowed_hosts,
+            decompress_responses,
+            config,
         })
     }
 
+    /// Register the host calls on `linker`.
+    ///
+    /// Because `req` and `body_read` are async host functions, `linker` must be
+    /// attached to an async-enabled `Store` and the guest invoked via
+    /// `call_async`; see the [`Http`] type docs.
     pub fn add_to_linker(&self, linker: &mut Linker) -> Result<(), Error> {
         let st = self.state.clone();
         linker.func(
@@ -262,26 +515,23 @@ impl Http {
         )?;
 
         let st = self.state.clone();
-        linker.func(
+        linker.func_async(
             Self::MODULE,
             "body_read",
             move |caller: Caller<'_>,
                   handle: WasiHandle,
                   buf_ptr: u32,
                   buf_len: u32,
-                  buf_read_ptr: u32|
-                  -> u32 {
-                match HostCalls::body_read(
-                    st.clone(),
-                    caller,
-                    handle,
-                    buf_ptr,
-                    buf_len,
-                    buf_read_ptr,
-                ) {
-                    Ok(()) => 0,
-                    Err(e) => e.into(),
-                }
+                  buf_read_ptr: u32| {
+                let st = st.clone();
+                Box::new(async move {
+                    match HostCalls::body_read(st, caller, handle, buf_ptr, buf_len, buf_read_ptr)
+                        .await
+                    {
+                        Ok(()) => 0,
+                        Err(e) => e.into(),
+                    }
+                })
             },
         )?;
 
@@ -314,8 +564,46 @@ impl Http {
         )?;
 
         let st = self.state.clone();
-        let allowed_hosts = self.allowed_hosts.clone();
         linker.func(
+            Self::MODULE,
+            "header_names",
+            move |caller: Caller<'_>,
+                  handle: WasiHandle,
+                  buf_ptr: u32,
+                  buf_len: u32,
+                  buf_written_ptr: u32|
+                  -> u32 {
+                match HostCalls::header_names(
+                    st.clone(),
+                    caller,
+                    handle,
+                    buf_ptr,
+                    buf_len,
+                    buf_written_ptr,
+                ) {
+                    Ok(()) => 0,
+                    Err(e) => e.into(),
+                }
+            },
+        )?;
+
+        let st = self.state.clone();
+        linker.func(
+            Self::MODULE,
+            "header_count",
+            move |caller: Caller<'_>, handle: WasiHandle, count_ptr: u32| -> u32 {
+                match HostCalls::header_count(st.clone(), caller, handle, count_ptr) {
+                    Ok(()) => 0,
+                    Err(e) => e.into(),
+                }
+            },
+        )?;
+
+        let st = self.state.clone();
+        let allowed_hosts = self.allowed_hosts.clone();
+        let decompress_responses = self.decompress_responses;
+        let config = self.config;
+        linker.func_async(
             Self::MODULE,
             "req",
             move |caller: Caller<'_>,
@@ -328,26 +616,33 @@ impl Http {
                   req_body_ptr: u32,
                   req_body_len: u32,
                   status_code_ptr: u32,
-                  res_handle_ptr: u32|
-                  -> u32 {
-                match HostCalls::req(
-                    st.clone(),
-                    allowed_hosts.as_deref(),
-                    caller,
-                    url_ptr,
-                    url_len,
-                    method_ptr,
-                    method_len,
-                    req_headers_ptr,
-                    req_headers_len,
-                    req_body_ptr,
-                    req_body_len,
-                    status_code_ptr,
-                    res_handle_ptr,
-                ) {
-                    Ok(()) => 0,
-                    Err(e) => e.into(),
-                }
+                  res_handle_ptr: u32| {
+                let st = st.clone();
+                let allowed_hosts = allowed_hosts.clone();
+                Box::new(async move {
+                    match HostCalls::req(
+                        st,
+                        allowed_hosts,
+                        decompress_responses,
+                        config,
+                        caller,
+                        url_ptr,
+                        url_len,
+                        method_ptr,
+                        method_len,
+                        req_headers_ptr,
+                        req_headers_len,
+                        req_body_ptr,
+                        req_body_len,
+                        status_code_ptr,
+                        res_handle_ptr,
+                    )
+                    .await
+                    {
+                        Ok(()) => 0,
+                        Err(e) => e.into(),
+                    }
+                })
             },
         )?;
 
@@ -355,13 +650,14 @@ impl Http {
     }
 }
 
-#[tracing::instrument]
-fn request(
+#[tracing::instrument(skip(body))]
+async fn request(
     url: &str,
     headers: HeaderMap,
     method: Method,
-    body: &[u8],
-) -> Result<(u16, HeaderMap<HeaderValue>, Bytes), HttpError> {
+    body: Vec<u8>,
+    config: &HttpConfig,
+) -> Result<(u16, HeaderMap<HeaderValue>, reqwest::Response), HttpError> {
     tracing::debug!(
         %url,
         ?headers,
@@ -370,59 +666,262 @@ fn request(
         "performing request"
     );
     let url: Url = url.parse().map_err(|_| HttpError::InvalidUrl)?;
-    let body = body.to_vec();
-    match Handle::try_current() {
-        Ok(r) => {
-            // If running in a Tokio runtime, spawn a new blocking executor
-            // that will send the HTTP request, and block on its execution.
-            // This attempts to avoid any deadlocks from other operations
-            // already executing on the same executor (compared with just
-            // blocking on the current one).
-            //
-            // This should only be a temporary workaround, until we take
-            // advantage of async functions in Wasmtime.
-            tracing::trace!("tokio runtime available, spawning request on tokio thread");
-            block_on(r.spawn_blocking(move || {
-                let client = Client::builder().build().unwrap();
-                let res = block_on(
-                    client
-                        .request(method, url)
-                        .headers(headers)
-                        .body(body)
-                        .send(),
-                )?;
-                Ok((
-                    res.status().as_u16(),
-                    res.headers().clone(),
-                    block_on(res.bytes())?,
-                ))
-            }))
-            .map_err(|_| HttpError::RuntimeError)?
+
+    // Build a single async client honoring the configured policy, and await
+    // the request on the caller's runtime. There is no longer any nested
+    // `block_on`/`spawn_blocking` dance: the host cooperatively yields while
+    // the request is in flight.
+    let mut builder =
+        Client::builder().redirect(reqwest::redirect::Policy::limited(config.max_redirects));
+    if let Some(t) = config.connect_timeout {
+        builder = builder.connect_timeout(t);
+    }
+    // NB: `config.timeout` is applied around `send()` below rather than via
+    // `Client::timeout`, which would also clock the lazily-read body.
+    let client = builder.build().map_err(map_reqwest_error)?;
+
+    let mut attempt = 0;
+    loop {
+        let send = client
+            .request(method.clone(), url.clone())
+            .headers(headers.clone())
+            .body(body.clone())
+            .send();
+        // Bound connect + headers only; the body is consumed later, at the
+        // guest's pace, outside this timeout.
+        let outcome = match config.timeout {
+            Some(t) => tokio::time::timeout(t, send).await,
+            None => Ok(send.await),
+        };
+        match outcome {
+            Ok(Ok(res)) => {
+                return Ok((res.status().as_u16(), res.headers().clone(), res));
+            }
+            Ok(Err(e)) if should_retry(&e, &method, attempt, config.retries) => {
+                attempt += 1;
+                tokio::time::sleep(config.backoff * attempt).await;
+            }
+            Ok(Err(e)) => return Err(map_reqwest_error(e)),
+            Err(_elapsed) if attempt < config.retries && is_idempotent(&method) => {
+                attempt += 1;
+                tokio::time::sleep(config.backoff * attempt).await;
+            }
+            Err(_elapsed) => return Err(HttpError::Timeout),
+        }
+    }
+}
+
+/// Whether the response `Content-Encoding` names an encoding we can inflate.
+fn supported_encoding(headers: &HeaderMap) -> bool {
+    headers
+        .get(http::header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| {
+            matches!(
+                v.trim().to_ascii_lowercase().as_str(),
+                "gzip" | "x-gzip" | "deflate" | "br"
+            )
+        })
+        .unwrap_or(false)
+}
+
+/// Map a `reqwest` error to the matching `HttpError`, distinguishing timeouts
+/// so guests can react to them specifically.
+fn map_reqwest_error(e: reqwest::Error) -> HttpError {
+    if e.is_timeout() {
+        HttpError::Timeout
+    } else {
+        HttpError::RequestError(e)
+    }
+}
+
+/// Whether a failed attempt should be retried: only for idempotent methods,
+/// and only while attempts remain.
+fn should_retry(_e: &reqwest::Error, method: &Method, attempt: u32, retries: u32) -> bool {
+    attempt < retries && is_idempotent(method)
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET
+            | Method::HEAD
+            | Method::PUT
+            | Method::DELETE
+            | Method::OPTIONS
+            | Method::TRACE
+    )
+}
+
+/// Inflate `body` according to the response `Content-Encoding` header.
+///
+/// Supports `gzip`, `deflate`, and `br`. On success the `Content-Encoding`
+/// header is removed and `Content-Length` rewritten to the decoded length so
+/// that `header_get` describes the bytes the guest actually reads. Absent or
+/// unrecognized encodings leave the body untouched.
+fn decode_response_body(headers: &mut HeaderMap, body: Bytes) -> Result<Vec<u8>, HttpError> {
+    use std::io::Read;
+
+    let encoding = match headers.get(http::header::CONTENT_ENCODING) {
+        Some(value) => value
+            .to_str()
+            .map_err(|_| HttpError::InvalidEncoding)?
+            .trim()
+            .to_ascii_lowercase(),
+        None => return Ok(body.to_vec()),
+    };
+
+    let decoded = match encoding.as_str() {
+        "gzip" | "x-gzip" => {
+            let mut buf = Vec::new();
+            flate2::read::GzDecoder::new(body.as_ref())
+                .read_to_end(&mut buf)
+                .map_err(|_| HttpError::DecodingError)?;
+            buf
         }
-        Err(_) => {
-            tracing::trace!("no tokio runtime available, using blocking request");
-            let res = reqwest::blocking::Client::new()
-                .request(method, url)
-                .headers(headers)
-                .body(body)
-                .send()?;
-            return Ok((res.status().as_u16(), res.headers().clone(), res.bytes()?));
+        "deflate" => {
+            // `Content-Encoding: deflate` is officially zlib-wrapped, but many
+            // servers send raw/headerless DEFLATE. Try zlib first, then fall
+            // back to raw inflate before giving up.
+            let mut buf = Vec::new();
+            if flate2::read::ZlibDecoder::new(body.as_ref())
+                .read_to_end(&mut buf)
+                .is_err()
+            {
+                buf.clear();
+                flate2::read::DeflateDecoder::new(body.as_ref())
+                    .read_to_end(&mut buf)
+                    .map_err(|_| HttpError::DecodingError)?;
+            }
+            buf
         }
+        "br" => {
+            let mut buf = Vec::new();
+            brotli::Decompressor::new(body.as_ref(), 4096)
+                .read_to_end(&mut buf)
+                .map_err(|_| HttpError::DecodingError)?;
+            buf
+        }
+        _ => return Ok(body.to_vec()),
+    };
+
+    headers.remove(http::header::CONTENT_ENCODING);
+    if headers.contains_key(http::header::CONTENT_LENGTH) {
+        let len = HeaderValue::from_str(&decoded.len().to_string())
+            .map_err(|_| HttpError::DecodingError)?;
+        headers.insert(http::header::CONTENT_LENGTH, len);
+    }
+    Ok(decoded)
+}
+
+/// How the host component of an allow-list entry is matched against a request.
+enum HostMatch {
+    /// The request host must equal this host exactly.
+    Exact(String),
+    /// A `*.suffix` entry: the request host must be a strict subdomain of the
+    /// suffix (e.g. `*.example.com` matches `api.example.com` but not
+    /// `example.com`).
+    Suffix(String),
+}
+
+/// A parsed allow-list entry. Each component is only enforced when the entry
+/// specifies it, so a bare `example.com` keeps the original host-only
+/// semantics while `https://api.example.com:8443/v1` constrains scheme, host,
+/// port, and path prefix all at once.
+struct AllowEntry {
+    scheme: Option<String>,
+    host: HostMatch,
+    port: Option<u16>,
+    path_prefix: Option<String>,
+}
+
+impl AllowEntry {
+    fn parse(entry: &str) -> Result<Self, HttpError> {
+        let (scheme, rest) = match entry.split_once("://") {
+            Some((s, r)) => (Some(s.to_ascii_lowercase()), r),
+            None => (None, entry),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, ""),
+        };
+        let path_prefix = match path.trim_end_matches('/') {
+            "" => None,
+            p => Some(p.to_string()),
+        };
+        // Split off a trailing `:NNN` port, but not the colons inside a
+        // bracketed IPv6 literal: `[::1]` has no port, `[::1]:8080` does.
+        let (host_str, port) = if authority.ends_with(']') {
+            (authority, None)
+        } else {
+            match authority.rsplit_once(':') {
+                Some((h, p)) => (h, Some(p.parse::<u16>().map_err(|_| HttpError::InvalidUrl)?)),
+                None => (authority, None),
+            }
+        };
+        let host = match host_str.to_ascii_lowercase().strip_prefix("*.") {
+            Some(suffix) => HostMatch::Suffix(suffix.to_string()),
+            None => HostMatch::Exact(host_str.to_ascii_lowercase()),
+        };
+        Ok(AllowEntry {
+            scheme,
+            host,
+            port,
+            path_prefix,
+        })
+    }
+
+    fn matches(&self, url: &Url) -> bool {
+        if let Some(scheme) = &self.scheme {
+            if url.scheme() != scheme {
+                return false;
+            }
+        }
+        let req_host = match url.host_str() {
+            Some(h) => h.to_ascii_lowercase(),
+            None => return false,
+        };
+        match &self.host {
+            HostMatch::Exact(h) => {
+                if &req_host != h {
+                    return false;
+                }
+            }
+            HostMatch::Suffix(suffix) => {
+                if req_host.len() <= suffix.len() || !req_host.ends_with(suffix) {
+                    return false;
+                }
+                let boundary = req_host.len() - suffix.len() - 1;
+                if req_host.as_bytes()[boundary] != b'.' {
+                    return false;
+                }
+            }
+        }
+        if let Some(port) = self.port {
+            if url.port_or_known_default() != Some(port) {
+                return false;
+            }
+        }
+        if let Some(prefix) = &self.path_prefix {
+            let path = url.path();
+            if path != prefix && !path.starts_with(&format!("{}/", prefix)) {
+                return false;
+            }
+        }
+        true
     }
 }
 
 fn is_allowed(url: &str, allowed_domains: Option<&[String]>) -> Result<bool, HttpError> {
-    let url_host = Url::parse(url)
-        .map_err(|_| HttpError::InvalidUrl)?
-        .host_str()
-        .ok_or(HttpError::InvalidUrl)?
-        .to_owned();
+    let url = Url::parse(url).map_err(|_| HttpError::InvalidUrl)?;
     match allowed_domains {
         Some(domains) => {
-            let allowed: Result<Vec<_>, _> = domains.iter().map(|d| Url::parse(d)).collect();
-            let allowed = allowed.map_err(|_| HttpError::InvalidUrl)?;
-            let a: Vec<&str> = allowed.iter().map(|u| u.host_str().unwrap()).collect();
-            Ok(a.contains(&url_host.as_str()))
+            for domain in domains {
+                if AllowEntry::parse(domain)?.matches(&url) {
+                    return Ok(true);
+                }
+            }
+            Ok(false)
         }
         None => Ok(false),
     }
@@ -461,3 +960,38 @@ fn test_allowed_domains() {
         is_allowed("https://test.brigade.sh", Some(allowed_domains.as_ref())).unwrap()
     );
 }
+
+#[test]
+fn test_allowed_domains_granular() {
+    let allowed_domains = vec![
+        "https://example.com".to_string(),
+        "*.wildcard.com".to_string(),
+        "https://api.example.com/v1".to_string(),
+        "http://localhost:8080".to_string(),
+        "http://[::1]".to_string(),
+        "http://[::2]:8080".to_string(),
+    ];
+    let allowed = |url| is_allowed(url, Some(allowed_domains.as_ref())).unwrap();
+
+    // Scheme is enforced when the entry specifies one.
+    assert_eq!(true, allowed("https://example.com/path"));
+    assert_eq!(false, allowed("http://example.com/path"));
+
+    // `*.` matches any subdomain but not the bare suffix.
+    assert_eq!(true, allowed("https://sub.wildcard.com/"));
+    assert_eq!(true, allowed("http://deep.sub.wildcard.com/"));
+    assert_eq!(false, allowed("https://wildcard.com/"));
+
+    // A path prefix restricts to URLs under that path.
+    assert_eq!(true, allowed("https://api.example.com/v1/users"));
+    assert_eq!(false, allowed("https://api.example.com/v2/users"));
+
+    // A specified port must match.
+    assert_eq!(true, allowed("http://localhost:8080/health"));
+    assert_eq!(false, allowed("http://localhost:9090/health"));
+
+    // IPv6 literals are matched, with or without a port.
+    assert_eq!(true, allowed("http://[::1]/login"));
+    assert_eq!(true, allowed("http://[::2]:8080/login"));
+    assert_eq!(false, allowed("http://[::2]:9090/login"));
+}